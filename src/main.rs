@@ -1,21 +1,44 @@
 //! # git-subdir
-//! 
-//! Simple command line tool to download a sub directory from a github repo.
+//!
+//! Simple command line tool to download a sub directory from a git repo.
+//! Supports GitHub, GitLab and Bitbucket.
 
-use clap::Parser;
+use clap::{Parser, ValueEnum};
+use futures::future::{join_all, BoxFuture};
 use reqwest;
 use scraper::{Html, Selector};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use sha2::{Digest, Sha256};
 use std::{
+    collections::BTreeMap,
     fmt, fs,
     path::{Path, PathBuf},
+    process::Command,
+    sync::{Arc, Mutex},
 };
+use tokio::sync::Semaphore;
+
+/// Name of the manifest written alongside downloaded files.
+const LOCKFILE_NAME: &str = "git-subdir.lock";
+
+/// Which mechanism to use to fetch the subdirectory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Backend {
+    /// Scrape or query the provider's directory-listing API.
+    Scrape,
+    /// Shell out to the system `git` binary and do a blobless sparse checkout.
+    Git,
+}
 
 #[derive(Parser)]
 #[command(version = "0.1")]
-#[command(about="download a subdirectory from github repo", long_about=None)]
+#[command(about="download a subdirectory from a github/gitlab/bitbucket repo", long_about=None)]
 struct Cli {
-    /// Github url
+    /// Repo url, e.g. `https://github.com/user/repo/tree/branch/path`.
+    ///
+    /// GitLab and Bitbucket urls are also accepted, as are the short aliases
+    /// `gh:user/repo/tree/branch/path`, `gl:...` and `bb:...`.
     url: String,
 
     /// Output directory. Is created if it doesn't exist.
@@ -29,9 +52,88 @@ struct Cli {
     /// Write paths relative to given url rather than repo root.
     #[arg(short = 'r', long)]
     relative: bool,
+
+    /// Backend used to fetch the directory contents.
+    ///
+    /// `git` requires a system `git` binary but handles large trees and private
+    /// repos better than scraping. Falls back to `scrape` if `git` is not on `PATH`.
+    #[arg(long, value_enum, default_value_t = Backend::Scrape)]
+    backend: Backend,
+
+    /// Maximum number of files to download concurrently (scrape backend only).
+    #[arg(short = 'j', long, default_value_t = 8)]
+    jobs: usize,
+
+    /// Recompute local file hashes against the lockfile to detect tampering or
+    /// corrupt downloads (scrape backend only).
+    #[arg(long)]
+    verify: bool,
+
+    /// Pin to a specific tag, commit sha, or branch, overriding whatever `url` implies.
+    #[arg(long = "ref")]
+    git_ref: Option<String>,
+}
+
+/// The type of an entry returned by [`Repository::list_dir`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum RepoItemType {
+    File,
+    Directory,
+    Symlink,
 }
 
-struct GitHubUrl {
+/// A single entry in a directory listing.
+#[derive(Clone, Debug)]
+struct RepoItem {
+    name: String,
+    path: PathBuf,
+    item_type: RepoItemType,
+}
+
+/// A directory within a hosted git repo, pinned to a branch.
+///
+/// Implemented per-provider, since each host exposes its own url scheme and
+/// directory-listing format.
+trait Repository: Send + Sync {
+    /// Return url to directory.
+    fn url(&self) -> String;
+
+    /// Return url to get raw file.
+    fn raw_url(&self) -> String;
+
+    /// Return the name of the requested dir.
+    fn basename(&self) -> String;
+
+    /// Return the repo-relative path of the requested directory.
+    fn path(&self) -> &Path;
+
+    /// Return the branch/tag/commit this repository is pinned to.
+    fn branch(&self) -> &str;
+
+    /// Return the url `git clone` should use to fetch this repo.
+    fn clone_url(&self) -> String;
+
+    /// Return new Repository with `part` appended to the current path.
+    fn join(&self, part: &str) -> Arc<dyn Repository>;
+
+    /// Return new Repository pinned to `new_ref` instead of its current branch/tag/commit.
+    fn with_ref(&self, new_ref: &str) -> Arc<dyn Repository>;
+
+    /// List the contents of this directory.
+    fn list_dir<'a>(
+        &'a self,
+        client: &'a reqwest::Client,
+    ) -> BoxFuture<'a, Result<Vec<RepoItem>, String>>;
+
+    /// Resolve `branch()` to the commit SHA it currently points at.
+    fn resolve_ref<'a>(
+        &'a self,
+        client: &'a reqwest::Client,
+    ) -> BoxFuture<'a, Result<String, String>>;
+}
+
+#[derive(Clone)]
+struct GitHubRepo {
     site: String,
     raw_site: String,
     username: String,
@@ -40,14 +142,35 @@ struct GitHubUrl {
     path: PathBuf,
 }
 
-impl GitHubUrl {
-    /// Create new GitHubUrl instance
-    pub fn new(url: &String) -> Result<GitHubUrl, String> {
+impl GitHubRepo {
+    /// Create new GitHubRepo instance.
+    ///
+    /// Accepts the usual `tree/<branch>/<path>` url, as well as the explicit
+    /// `repo[.git]#<ref>:<path>` syntax, which unambiguously pins to a tag,
+    /// commit sha, or branch name containing slashes. For the `tree/` form,
+    /// since the branch/path boundary is itself ambiguous when the branch
+    /// contains slashes, this first checks via `client` whether the url exists
+    /// with the first path segment taken as the whole branch; if not, it
+    /// queries the repo's branch and tag names and matches the longest ref
+    /// prefix, and if that lookup fails, it keeps the first-segment guess.
+    pub async fn new(url: &str, client: &reqwest::Client) -> Result<GitHubRepo, String> {
         let prefix = "https://github.com";
         if !url.starts_with(prefix) {
             return Err(make_error_message(format!("'{}' is not a github url", url)));
         }
 
+        if let Some(result) = parse_explicit_ref(url, prefix) {
+            let (username, repo_name, branch, path) = result?;
+            return Ok(GitHubRepo {
+                site: String::from(prefix),
+                raw_site: String::from("https://raw.githubusercontent.com"),
+                username,
+                repo_name,
+                branch,
+                path,
+            });
+        }
+
         let url_parts: Vec<&str> = url
             .strip_prefix(prefix)
             .unwrap()
@@ -75,10 +198,52 @@ impl GitHubUrl {
         let raw_site = String::from("https://raw.githubusercontent.com");
         let username = String::from(url_parts[0]);
         let repo_name = String::from(url_parts[1]);
-        let branch = String::from(url_parts[3]);
-        let path = PathBuf::from(url_parts[4..].join("/"));
+        let ambiguous = &url_parts[3..];
+
+        let (branch, path) = if ambiguous.len() == 1 {
+            (String::from(ambiguous[0]), PathBuf::new())
+        } else {
+            // Most urls are unambiguous (`tree/main/src/...`), so try the cheap
+            // assumption first - the first segment is the whole branch - and only
+            // pay for the paginated branch/tag lookup if that guess 404s.
+            let candidate_branch = String::from(ambiguous[0]);
+            let candidate_path = PathBuf::from(ambiguous[1..].join("/"));
+            let candidate_url = format!(
+                "{}/{}/{}/tree/{}/{}",
+                site,
+                username,
+                repo_name,
+                candidate_branch,
+                candidate_path.to_str().unwrap()
+            );
+
+            let candidate_ok = client
+                .get(&candidate_url)
+                .send()
+                .await
+                .map(|r| r.status().is_success())
+                .unwrap_or(false);
+
+            if candidate_ok {
+                (candidate_branch, candidate_path)
+            } else {
+                match fetch_github_ref_names(client, &username, &repo_name).await {
+                    Ok(refs) => resolve_ref_boundary(&refs, ambiguous),
+                    Err(e) => {
+                        println!(
+                            "{}",
+                            make_warning_message(format!(
+                                "could not resolve branch boundary for '{}/{}', assuming '{}' is the whole branch name: {}",
+                                username, repo_name, ambiguous[0], e
+                            ))
+                        );
+                        (candidate_branch, candidate_path)
+                    }
+                }
+            }
+        };
 
-        Ok(GitHubUrl {
+        Ok(GitHubRepo {
             site,
             raw_site,
             username,
@@ -87,9 +252,10 @@ impl GitHubUrl {
             path,
         })
     }
+}
 
-    /// Return url to directory
-    pub fn url(&self) -> String {
+impl Repository for GitHubRepo {
+    fn url(&self) -> String {
         format!(
             "{}/{}/{}/tree/{}/{}",
             self.site,
@@ -100,8 +266,7 @@ impl GitHubUrl {
         )
     }
 
-    /// Return url to get raw file
-    pub fn raw_url(&self) -> String {
+    fn raw_url(&self) -> String {
         format!(
             "{}/{}/{}/{}/{}",
             self.raw_site,
@@ -110,11 +275,9 @@ impl GitHubUrl {
             self.branch,
             self.path.to_str().unwrap()
         )
-        // format!("{}?raw=true", self.url())
     }
 
-    /// Return the name of the requested dir
-    pub fn basename(&self) -> String {
+    fn basename(&self) -> String {
         String::from(
             self.path
                 .components()
@@ -126,18 +289,601 @@ impl GitHubUrl {
         )
     }
 
-    /// Return new GitHubUrl with `part` appended
-    pub fn join(&self, part: &str) -> GitHubUrl {
-        let new_url = format!("{}/{}", self.url(), part);
-        GitHubUrl::new(&new_url).unwrap()
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn branch(&self) -> &str {
+        &self.branch
+    }
+
+    fn clone_url(&self) -> String {
+        format!("{}/{}/{}.git", self.site, self.username, self.repo_name)
+    }
+
+    fn join(&self, part: &str) -> Arc<dyn Repository> {
+        Arc::new(GitHubRepo {
+            path: self.path.join(part),
+            ..self.clone()
+        })
+    }
+
+    fn with_ref(&self, new_ref: &str) -> Arc<dyn Repository> {
+        Arc::new(GitHubRepo {
+            branch: String::from(new_ref),
+            ..self.clone()
+        })
+    }
+
+    fn list_dir<'a>(
+        &'a self,
+        client: &'a reqwest::Client,
+    ) -> BoxFuture<'a, Result<Vec<RepoItem>, String>> {
+        Box::pin(async move {
+            let text = client
+                .get(self.url())
+                .send()
+                .await
+                .map_err(|e| make_error_message(format!("could not fetch '{}': {}", self.url(), e)))?
+                .text()
+                .await
+                .map_err(|e| {
+                    make_error_message(format!(
+                        "could not read response from '{}': {}",
+                        self.url(),
+                        e
+                    ))
+                })?;
+
+            // find table of items in html
+            let document = Html::parse_document(&text);
+            let selector = Selector::parse(
+                r#"script[type="application/json"][data-target="react-app.embeddedData"]"#,
+            )
+            .unwrap();
+
+            let mut result = Vec::new();
+            for title in document.select(&selector) {
+                let v: Value = serde_json::from_str(&title.inner_html()).map_err(|e| {
+                    make_error_message(format!("could not parse listing for '{}': {}", self.url(), e))
+                })?;
+
+                let items = v["payload"]["tree"]["items"].as_array().ok_or_else(|| {
+                    make_error_message(format!(
+                        "unexpected listing shape for '{}' (GitHub page format may have changed)",
+                        self.url()
+                    ))
+                })?;
+                for item in items {
+                    let name = item["name"]
+                        .as_str()
+                        .ok_or_else(|| {
+                            make_error_message(format!(
+                                "unexpected listing shape for '{}' (GitHub page format may have changed)",
+                                self.url()
+                            ))
+                        })?
+                        .to_string();
+                    let path = PathBuf::from(item["path"].as_str().ok_or_else(|| {
+                        make_error_message(format!(
+                            "unexpected listing shape for '{}' (GitHub page format may have changed)",
+                            self.url()
+                        ))
+                    })?);
+                    let item_type = match item["contentType"].as_str().ok_or_else(|| {
+                        make_error_message(format!(
+                            "unexpected listing shape for '{}' (GitHub page format may have changed)",
+                            self.url()
+                        ))
+                    })? {
+                        "file" => RepoItemType::File,
+                        "directory" => RepoItemType::Directory,
+                        "symlink_file" | "symlink_directory" => RepoItemType::Symlink,
+                        other => {
+                            return Err(make_error_message(format!(
+                                "cannot handle item type '{}'",
+                                other
+                            )))
+                        }
+                    };
+                    result.push(RepoItem {
+                        name,
+                        path,
+                        item_type,
+                    });
+                }
+            }
+
+            Ok(result)
+        })
+    }
+
+    fn resolve_ref<'a>(
+        &'a self,
+        client: &'a reqwest::Client,
+    ) -> BoxFuture<'a, Result<String, String>> {
+        Box::pin(async move {
+            let api_url = format!(
+                "https://api.github.com/repos/{}/{}/commits/{}",
+                self.username, self.repo_name, self.branch
+            );
+            let v: Value = client
+                .get(&api_url)
+                .header("Accept", "application/vnd.github+json")
+                .send()
+                .await
+                .map_err(|e| make_error_message(format!("could not resolve '{}': {}", api_url, e)))?
+                .json()
+                .await
+                .map_err(|e| make_error_message(format!("could not resolve '{}': {}", api_url, e)))?;
+
+            v["sha"]
+                .as_str()
+                .map(String::from)
+                .ok_or_else(|| make_error_message(format!("no commit sha found for '{}'", api_url)))
+        })
+    }
+}
+
+impl fmt::Display for GitHubRepo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "GitHubRepo {{\n  username: {},\n  repo: {},\n  branch: {},\n  path: {}\n}}",
+            self.username,
+            self.repo_name,
+            self.branch,
+            self.path.to_str().unwrap()
+        )
+    }
+}
+
+#[derive(Clone)]
+struct GitLabRepo {
+    site: String,
+    username: String,
+    repo_name: String,
+    branch: String,
+    path: PathBuf,
+}
+
+impl GitLabRepo {
+    /// Create new GitLabRepo instance
+    pub fn new(url: &str) -> Result<GitLabRepo, String> {
+        let prefix = "https://gitlab.com";
+        if !url.starts_with(prefix) {
+            return Err(make_error_message(format!("'{}' is not a gitlab url", url)));
+        }
+
+        let url_parts: Vec<&str> = url
+            .strip_prefix(prefix)
+            .unwrap()
+            .split("/")
+            .filter(|s| !s.is_empty())
+            .collect();
+        if url_parts.len() == 2 {
+            return Err(make_error_message(format!(
+                "{}' is a top-level git repo.\nInstead, try:\n  {}",
+                url,
+                highlight_message(format!("git clone {}", url))
+            )));
+        } else if url_parts.len() < 5 {
+            return Err(make_error_message(format!(
+                "'{}' is not a url to a directory within a gitlab repo",
+                url
+            )));
+        }
+
+        if url_parts[2] != "-" || url_parts[3] != "tree" {
+            return Err(make_error_message(format!("cannot parse url '{}'", url)));
+        }
+
+        let site = String::from(prefix);
+        let username = String::from(url_parts[0]);
+        let repo_name = String::from(url_parts[1]);
+        let branch = String::from(url_parts[4]);
+        let path = PathBuf::from(url_parts[5..].join("/"));
+
+        Ok(GitLabRepo {
+            site,
+            username,
+            repo_name,
+            branch,
+            path,
+        })
+    }
+
+    /// Url of the GitLab API endpoint listing this directory's contents.
+    fn api_url(&self) -> String {
+        format!(
+            "{}/api/v4/projects/{}%2F{}/repository/tree?path={}&ref={}&per_page=100",
+            self.site,
+            self.username,
+            self.repo_name,
+            self.path.to_str().unwrap(),
+            self.branch
+        )
+    }
+}
+
+impl Repository for GitLabRepo {
+    fn url(&self) -> String {
+        format!(
+            "{}/{}/{}/-/tree/{}/{}",
+            self.site,
+            self.username,
+            self.repo_name,
+            self.branch,
+            self.path.to_str().unwrap()
+        )
+    }
+
+    fn raw_url(&self) -> String {
+        format!(
+            "{}/{}/{}/-/raw/{}/{}",
+            self.site,
+            self.username,
+            self.repo_name,
+            self.branch,
+            self.path.to_str().unwrap()
+        )
+    }
+
+    fn basename(&self) -> String {
+        String::from(
+            self.path
+                .components()
+                .next_back()
+                .unwrap()
+                .as_os_str()
+                .to_str()
+                .unwrap(),
+        )
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn branch(&self) -> &str {
+        &self.branch
+    }
+
+    fn clone_url(&self) -> String {
+        format!("{}/{}/{}.git", self.site, self.username, self.repo_name)
+    }
+
+    fn join(&self, part: &str) -> Arc<dyn Repository> {
+        Arc::new(GitLabRepo {
+            path: self.path.join(part),
+            ..self.clone()
+        })
+    }
+
+    fn with_ref(&self, new_ref: &str) -> Arc<dyn Repository> {
+        Arc::new(GitLabRepo {
+            branch: String::from(new_ref),
+            ..self.clone()
+        })
+    }
+
+    fn list_dir<'a>(
+        &'a self,
+        client: &'a reqwest::Client,
+    ) -> BoxFuture<'a, Result<Vec<RepoItem>, String>> {
+        Box::pin(async move {
+            let mut result = Vec::new();
+            let mut url = self.api_url();
+
+            loop {
+                let response = client
+                    .get(&url)
+                    .send()
+                    .await
+                    .map_err(|e| make_error_message(format!("could not fetch '{}': {}", url, e)))?;
+
+                let next_url = response
+                    .headers()
+                    .get("link")
+                    .and_then(|h| h.to_str().ok())
+                    .and_then(parse_link_next);
+
+                let items: Vec<Value> = response.json().await.map_err(|e| {
+                    make_error_message(format!("could not parse listing for '{}': {}", url, e))
+                })?;
+
+                for item in items {
+                    let name = item["name"].as_str().unwrap().to_string();
+                    let path = PathBuf::from(item["path"].as_str().unwrap());
+                    let item_type = if item["mode"].as_str() == Some("120000") {
+                        RepoItemType::Symlink
+                    } else {
+                        match item["type"].as_str().unwrap() {
+                            "blob" => RepoItemType::File,
+                            "tree" => RepoItemType::Directory,
+                            other => {
+                                return Err(make_error_message(format!(
+                                    "cannot handle item type '{}'",
+                                    other
+                                )))
+                            }
+                        }
+                    };
+                    result.push(RepoItem {
+                        name,
+                        path,
+                        item_type,
+                    });
+                }
+
+                match next_url {
+                    Some(next) => url = next,
+                    None => break,
+                }
+            }
+
+            Ok(result)
+        })
+    }
+
+    fn resolve_ref<'a>(
+        &'a self,
+        client: &'a reqwest::Client,
+    ) -> BoxFuture<'a, Result<String, String>> {
+        Box::pin(async move {
+            let api_url = format!(
+                "{}/api/v4/projects/{}%2F{}/repository/commits/{}",
+                self.site, self.username, self.repo_name, self.branch
+            );
+            let v: Value = client
+                .get(&api_url)
+                .send()
+                .await
+                .map_err(|e| make_error_message(format!("could not resolve '{}': {}", api_url, e)))?
+                .json()
+                .await
+                .map_err(|e| make_error_message(format!("could not resolve '{}': {}", api_url, e)))?;
+
+            v["id"]
+                .as_str()
+                .map(String::from)
+                .ok_or_else(|| make_error_message(format!("no commit sha found for '{}'", api_url)))
+        })
+    }
+}
+
+impl fmt::Display for GitLabRepo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "GitLabRepo {{\n  username: {},\n  repo: {},\n  branch: {},\n  path: {}\n}}",
+            self.username,
+            self.repo_name,
+            self.branch,
+            self.path.to_str().unwrap()
+        )
+    }
+}
+
+#[derive(Clone)]
+struct BitbucketRepo {
+    site: String,
+    username: String,
+    repo_name: String,
+    branch: String,
+    path: PathBuf,
+}
+
+impl BitbucketRepo {
+    /// Create new BitbucketRepo instance
+    pub fn new(url: &str) -> Result<BitbucketRepo, String> {
+        let prefix = "https://bitbucket.org";
+        if !url.starts_with(prefix) {
+            return Err(make_error_message(format!(
+                "'{}' is not a bitbucket url",
+                url
+            )));
+        }
+
+        let url_parts: Vec<&str> = url
+            .strip_prefix(prefix)
+            .unwrap()
+            .split("/")
+            .filter(|s| !s.is_empty())
+            .collect();
+        if url_parts.len() == 2 {
+            return Err(make_error_message(format!(
+                "{}' is a top-level git repo.\nInstead, try:\n  {}",
+                url,
+                highlight_message(format!("git clone {}", url))
+            )));
+        } else if url_parts.len() < 4 {
+            return Err(make_error_message(format!(
+                "'{}' is not a url to a directory within a bitbucket repo",
+                url
+            )));
+        }
+
+        if url_parts[2] != "src" {
+            return Err(make_error_message(format!("cannot parse url '{}'", url)));
+        }
+
+        let site = String::from(prefix);
+        let username = String::from(url_parts[0]);
+        let repo_name = String::from(url_parts[1]);
+        let branch = String::from(url_parts[3]);
+        let path = PathBuf::from(url_parts[4..].join("/"));
+
+        Ok(BitbucketRepo {
+            site,
+            username,
+            repo_name,
+            branch,
+            path,
+        })
+    }
+
+    /// Url of the Bitbucket API endpoint listing this directory's contents.
+    fn api_url(&self) -> String {
+        format!(
+            "https://api.bitbucket.org/2.0/repositories/{}/{}/src/{}/{}?pagelen=100",
+            self.username,
+            self.repo_name,
+            self.branch,
+            self.path.to_str().unwrap()
+        )
+    }
+}
+
+impl Repository for BitbucketRepo {
+    fn url(&self) -> String {
+        format!(
+            "{}/{}/{}/src/{}/{}",
+            self.site,
+            self.username,
+            self.repo_name,
+            self.branch,
+            self.path.to_str().unwrap()
+        )
+    }
+
+    fn raw_url(&self) -> String {
+        format!(
+            "{}/{}/{}/raw/{}/{}",
+            self.site,
+            self.username,
+            self.repo_name,
+            self.branch,
+            self.path.to_str().unwrap()
+        )
+    }
+
+    fn basename(&self) -> String {
+        String::from(
+            self.path
+                .components()
+                .next_back()
+                .unwrap()
+                .as_os_str()
+                .to_str()
+                .unwrap(),
+        )
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn branch(&self) -> &str {
+        &self.branch
+    }
+
+    fn clone_url(&self) -> String {
+        format!("{}/{}/{}.git", self.site, self.username, self.repo_name)
+    }
+
+    fn join(&self, part: &str) -> Arc<dyn Repository> {
+        Arc::new(BitbucketRepo {
+            path: self.path.join(part),
+            ..self.clone()
+        })
+    }
+
+    fn with_ref(&self, new_ref: &str) -> Arc<dyn Repository> {
+        Arc::new(BitbucketRepo {
+            branch: String::from(new_ref),
+            ..self.clone()
+        })
+    }
+
+    fn list_dir<'a>(
+        &'a self,
+        client: &'a reqwest::Client,
+    ) -> BoxFuture<'a, Result<Vec<RepoItem>, String>> {
+        Box::pin(async move {
+            let mut result = Vec::new();
+            let mut url = self.api_url();
+
+            loop {
+                let payload: Value = client
+                    .get(&url)
+                    .send()
+                    .await
+                    .map_err(|e| make_error_message(format!("could not fetch '{}': {}", url, e)))?
+                    .json()
+                    .await
+                    .map_err(|e| {
+                        make_error_message(format!("could not parse listing for '{}': {}", url, e))
+                    })?;
+
+                let items = payload["values"].as_array().ok_or_else(|| {
+                    make_error_message(format!("unexpected response listing '{}'", url))
+                })?;
+
+                for item in items {
+                    let path = PathBuf::from(item["path"].as_str().unwrap());
+                    let name = String::from(
+                        path.components().next_back().unwrap().as_os_str().to_str().unwrap(),
+                    );
+                    let item_type = match item["type"].as_str().unwrap() {
+                        "commit_file" => RepoItemType::File,
+                        "commit_directory" => RepoItemType::Directory,
+                        "commit_symlink" => RepoItemType::Symlink,
+                        other => {
+                            return Err(make_error_message(format!(
+                                "cannot handle item type '{}'",
+                                other
+                            )))
+                        }
+                    };
+                    result.push(RepoItem {
+                        name,
+                        path,
+                        item_type,
+                    });
+                }
+
+                match payload["next"].as_str() {
+                    Some(next) => url = String::from(next),
+                    None => break,
+                }
+            }
+
+            Ok(result)
+        })
+    }
+
+    fn resolve_ref<'a>(
+        &'a self,
+        client: &'a reqwest::Client,
+    ) -> BoxFuture<'a, Result<String, String>> {
+        Box::pin(async move {
+            let api_url = format!(
+                "https://api.bitbucket.org/2.0/repositories/{}/{}/commit/{}",
+                self.username, self.repo_name, self.branch
+            );
+            let v: Value = client
+                .get(&api_url)
+                .send()
+                .await
+                .map_err(|e| make_error_message(format!("could not resolve '{}': {}", api_url, e)))?
+                .json()
+                .await
+                .map_err(|e| make_error_message(format!("could not resolve '{}': {}", api_url, e)))?;
+
+            v["hash"]
+                .as_str()
+                .map(String::from)
+                .ok_or_else(|| make_error_message(format!("no commit sha found for '{}'", api_url)))
+        })
     }
 }
 
-impl fmt::Display for GitHubUrl {
+impl fmt::Display for BitbucketRepo {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "GitHubUrl {{\n  username: {},\n  repo: {},\n  branch: {},\n  path: {}\n}}",
+            "BitbucketRepo {{\n  username: {},\n  repo: {},\n  branch: {},\n  path: {}\n}}",
             self.username,
             self.repo_name,
             self.branch,
@@ -146,6 +892,343 @@ impl fmt::Display for GitHubUrl {
     }
 }
 
+/// Extract the `rel="next"` url from an HTTP `Link` header value (RFC 8288),
+/// as used by GitHub's and GitLab's paginated APIs.
+fn parse_link_next(link_header: &str) -> Option<String> {
+    link_header.split(',').find_map(|part| {
+        let (url_part, rel_part) = part.split_once(';')?;
+        if rel_part.contains("rel=\"next\"") {
+            Some(String::from(
+                url_part.trim().trim_start_matches('<').trim_end_matches('>'),
+            ))
+        } else {
+            None
+        }
+    })
+}
+
+/// Expand the `gh:`/`gl:`/`bb:` short aliases to their full host url.
+fn expand_alias(url: &str) -> String {
+    if let Some(rest) = url.strip_prefix("gh:") {
+        format!("https://github.com/{}", rest)
+    } else if let Some(rest) = url.strip_prefix("gl:") {
+        format!("https://gitlab.com/{}", rest)
+    } else if let Some(rest) = url.strip_prefix("bb:") {
+        format!("https://bitbucket.org/{}", rest)
+    } else {
+        String::from(url)
+    }
+}
+
+/// Parse `url`, detecting which provider it belongs to.
+///
+/// Accepts full GitHub/GitLab/Bitbucket urls, as well as the `gh:`/`gl:`/`bb:`
+/// short aliases.
+async fn try_new(url: &str, client: &reqwest::Client) -> Result<Arc<dyn Repository>, String> {
+    let expanded = expand_alias(url);
+
+    if expanded.starts_with("https://github.com") {
+        GitHubRepo::new(&expanded, client)
+            .await
+            .map(|r| Arc::new(r) as Arc<dyn Repository>)
+    } else if expanded.starts_with("https://gitlab.com") {
+        GitLabRepo::new(&expanded).map(|r| Arc::new(r) as Arc<dyn Repository>)
+    } else if expanded.starts_with("https://bitbucket.org") {
+        BitbucketRepo::new(&expanded).map(|r| Arc::new(r) as Arc<dyn Repository>)
+    } else {
+        Err(make_error_message(format!(
+            "'{}' is not a recognised GitHub, GitLab or Bitbucket url",
+            url
+        )))
+    }
+}
+
+/// Parse the explicit `<prefix>/<user>/<repo>[.git]#<ref>:<path>` ref syntax.
+///
+/// Returns `None` if `url` has no `#<ref>:<path>` suffix, so callers can fall
+/// back to the ambiguous `tree/<branch>/<path>` form. Returns `Some(Err(_))`
+/// if the suffix is present but malformed.
+fn parse_explicit_ref(
+    url: &str,
+    prefix: &str,
+) -> Option<Result<(String, String, String, PathBuf), String>> {
+    let (repo_part, rest) = url.strip_prefix(prefix)?.split_once('#')?;
+    let repo_part = repo_part.trim_start_matches('/').trim_end_matches(".git");
+
+    let mut segments = repo_part.splitn(2, '/');
+    let username = segments.next().unwrap_or("");
+    let repo_name = segments.next().unwrap_or("");
+    if username.is_empty() || repo_name.is_empty() {
+        return Some(Err(make_error_message(format!("cannot parse url '{}'", url))));
+    }
+
+    let Some((branch, path)) = rest.split_once(':') else {
+        return Some(Err(make_error_message(format!(
+            "'{}' is missing a ':<path>' after the ref",
+            url
+        ))));
+    };
+    if branch.is_empty() || path.is_empty() {
+        return Some(Err(make_error_message(format!("cannot parse url '{}'", url))));
+    }
+
+    Some(Ok((
+        String::from(username),
+        String::from(repo_name),
+        String::from(branch),
+        PathBuf::from(path),
+    )))
+}
+
+/// Fetch the names of every branch and tag on `username/repo_name`, used to
+/// resolve the ambiguous branch/path boundary in a `tree/<branch>/<path>` url.
+async fn fetch_github_ref_names(
+    client: &reqwest::Client,
+    username: &str,
+    repo_name: &str,
+) -> Result<Vec<String>, String> {
+    async fn fetch_names(client: &reqwest::Client, url: &str) -> Result<Vec<String>, String> {
+        let mut names = Vec::new();
+        let mut url = String::from(url);
+
+        loop {
+            let response = client
+                .get(&url)
+                .header("Accept", "application/vnd.github+json")
+                .send()
+                .await
+                .map_err(|e| make_error_message(format!("could not fetch '{}': {}", url, e)))?;
+
+            let next_url = response
+                .headers()
+                .get("link")
+                .and_then(|h| h.to_str().ok())
+                .and_then(parse_link_next);
+
+            let items: Vec<Value> = response
+                .json()
+                .await
+                .map_err(|e| make_error_message(format!("could not parse '{}': {}", url, e)))?;
+
+            names.extend(items.iter().filter_map(|item| item["name"].as_str().map(String::from)));
+
+            match next_url {
+                Some(next) => url = next,
+                None => break,
+            }
+        }
+
+        Ok(names)
+    }
+
+    let branches_url = format!(
+        "https://api.github.com/repos/{}/{}/branches?per_page=100",
+        username, repo_name
+    );
+    let tags_url = format!(
+        "https://api.github.com/repos/{}/{}/tags?per_page=100",
+        username, repo_name
+    );
+
+    let mut refs = fetch_names(client, &branches_url).await?;
+    refs.extend(fetch_names(client, &tags_url).await?);
+    Ok(refs)
+}
+
+/// Split `ambiguous` into `(branch, path)` by matching the longest prefix
+/// (joined with `/`) found in `refs`. Falls back to treating the first
+/// segment as the whole branch if no ref matches.
+fn resolve_ref_boundary(refs: &[String], ambiguous: &[&str]) -> (String, PathBuf) {
+    for len in (1..=ambiguous.len()).rev() {
+        let candidate = ambiguous[..len].join("/");
+        if refs.iter().any(|r| r == &candidate) {
+            return (candidate, PathBuf::from(ambiguous[len..].join("/")));
+        }
+    }
+
+    (
+        String::from(ambiguous[0]),
+        PathBuf::from(ambiguous[1..].join("/")),
+    )
+}
+
+// GIT CLI BACKEND
+
+/// Return `true` if a `git` binary can be found on `PATH`.
+fn git_available() -> bool {
+    Command::new("git")
+        .arg("--version")
+        .output()
+        .map(|output| output.status.success())
+        .unwrap_or(false)
+}
+
+/// Run `git` with the given arguments, optionally in `cwd`.
+///
+/// # Arguments
+///
+/// * `args` - Arguments to pass to `git`.
+/// * `cwd` - Directory to run the command in. If `None`, uses the current directory.
+fn run_git(args: &[&str], cwd: Option<&Path>) -> Result<(), String> {
+    let mut cmd = Command::new("git");
+    cmd.args(args);
+    if let Some(dir) = cwd {
+        cmd.current_dir(dir);
+    }
+
+    let output = cmd.output().map_err(|e| {
+        make_error_message(format!("failed to run 'git {}': {}", args.join(" "), e))
+    })?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(make_error_message(format!(
+            "'git {}' failed: {}",
+            args.join(" "),
+            stderr.trim()
+        )));
+    }
+
+    Ok(())
+}
+
+/// Copy `src` to `dst`, recursing into subdirectories unless `ignore_subdirs` is set.
+fn copy_tree(src: &Path, dst: &Path, ignore_subdirs: bool) -> Result<(), String> {
+    if !dst.exists() {
+        make_dir(dst);
+    }
+
+    let entries = fs::read_dir(src)
+        .map_err(|e| make_error_message(format!("could not read '{}': {}", src.display(), e)))?;
+
+    for entry in entries {
+        let entry =
+            entry.map_err(|e| make_error_message(format!("could not read entry: {}", e)))?;
+        let path = entry.path();
+        let dst_path = dst.join(entry.file_name());
+
+        if path.is_dir() {
+            if path.file_name().map(|n| n == ".git").unwrap_or(false) {
+                continue;
+            }
+            if !ignore_subdirs {
+                copy_tree(&path, &dst_path, ignore_subdirs)?;
+            }
+        } else {
+            fs::copy(&path, &dst_path).map_err(|e| {
+                make_error_message(format!("could not copy '{}': {}", path.display(), e))
+            })?;
+            println!("Downloaded '{}'", dst_path.to_str().unwrap());
+        }
+    }
+
+    Ok(())
+}
+
+/// Return `true` if `git_ref` looks like a commit sha (a 7-40 character hex
+/// string) rather than a branch or tag name.
+fn looks_like_commit_sha(git_ref: &str) -> bool {
+    (7..=40).contains(&git_ref.len()) && git_ref.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Fetch a subdirectory via a blobless partial clone + sparse checkout, using the
+/// system `git` binary, instead of querying the provider's API.
+///
+/// `git clone --branch` only accepts branch/tag names, not commit shas, so
+/// when `url.branch()` looks like a sha this instead does a bare `init` +
+/// `remote add` + `fetch <sha>`, which GitHub/GitLab/Bitbucket all support
+/// for reachable commits.
+///
+/// # Arguments
+///
+/// * `url` - [`Repository`] pointing to the directory to fetch.
+/// * `output_path` - Directory to write to.
+/// * `ignore_subdirs` - If `true`, don't copy sub directories.
+/// * `relative_to` - If `Some(Repository)`, write relative to url. Otherwise, write files relative to repo root.
+fn get_subdir_git(
+    url: &dyn Repository,
+    output_path: &Path,
+    ignore_subdirs: bool,
+    relative_to: Option<&dyn Repository>,
+) -> Result<(), String> {
+    let repo_url = url.clone_url();
+    let path_str = url
+        .path()
+        .to_str()
+        .ok_or_else(|| make_error_message(String::from("path is not valid UTF-8")))?;
+
+    let tmp_dir = tempfile::tempdir()
+        .map_err(|e| make_error_message(format!("failed to create temp dir: {}", e)))?;
+    let tmp_path = tmp_dir.path();
+
+    if looks_like_commit_sha(url.branch()) {
+        run_git(&["init"], Some(tmp_path))?;
+        run_git(&["remote", "add", "origin", &repo_url], Some(tmp_path))?;
+        run_git(
+            &[
+                "fetch",
+                "--filter=blob:none",
+                "--depth",
+                "1",
+                "origin",
+                url.branch(),
+            ],
+            Some(tmp_path),
+        )?;
+        run_git(
+            &["sparse-checkout", "set", "--no-cone", path_str],
+            Some(tmp_path),
+        )?;
+        run_git(&["checkout", "FETCH_HEAD"], Some(tmp_path))?;
+    } else {
+        run_git(
+            &[
+                "clone",
+                "--filter=blob:none",
+                "--no-checkout",
+                "--depth",
+                "1",
+                "--branch",
+                url.branch(),
+                &repo_url,
+                tmp_path.to_str().unwrap(),
+            ],
+            None,
+        )?;
+        run_git(
+            &["sparse-checkout", "set", "--no-cone", path_str],
+            Some(tmp_path),
+        )?;
+        run_git(&["checkout"], Some(tmp_path))?;
+    }
+
+    let fetched_path = tmp_path.join(url.path());
+    if !fetched_path.exists() {
+        return Err(make_error_message(format!(
+            "'{}' does not exist on branch '{}'",
+            path_str,
+            url.branch()
+        )));
+    }
+
+    let dst_path = match relative_to {
+        Some(rel_url) => {
+            let rel = url.path().strip_prefix(rel_url.path()).unwrap_or(url.path());
+            let mut dst = output_path.to_path_buf();
+            dst.push(rel);
+            dst
+        }
+        None => {
+            let rel: PathBuf = url.path().components().skip(1).collect();
+            let mut dst = output_path.to_path_buf();
+            dst.push(rel);
+            dst
+        }
+    };
+
+    copy_tree(&fetched_path, &dst_path, ignore_subdirs)
+}
+
 /// Return a formatted error message.
 ///
 /// # Arguments
@@ -187,43 +1270,138 @@ fn make_dir(path: &Path) {
         .unwrap_or_else(|_| panic!("Could not create dir '{}'", path.to_str().unwrap()));
 }
 
-/// Download all items in github directory.
+/// Manifest recording, for a given commit, the SHA-256 of every downloaded file.
 ///
-/// Note this function is called recursively.
+/// Keyed by repo-relative path so the same lockfile stays meaningful regardless
+/// of `--output`/`--relative`.
+#[derive(Serialize, Deserialize, Clone)]
+struct Lockfile {
+    commit: String,
+    files: BTreeMap<PathBuf, String>,
+}
+
+/// Path to the lockfile written alongside `output_path`.
+fn lockfile_path(output_path: &Path) -> PathBuf {
+    output_path.join(LOCKFILE_NAME)
+}
+
+/// Read a previously-written lockfile, if any.
+fn read_lockfile(path: &Path) -> Option<Lockfile> {
+    let text = fs::read_to_string(path).ok()?;
+    serde_json::from_str(&text).ok()
+}
+
+/// Write `lock` to `path`, deterministically (sorted by path).
+fn write_lockfile(path: &Path, lock: &Lockfile) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(lock)
+        .map_err(|e| make_error_message(format!("could not serialize lockfile: {}", e)))?;
+    fs::write(path, json)
+        .map_err(|e| make_error_message(format!("could not write '{}': {}", path.display(), e)))
+}
+
+/// Hex-encoded SHA-256 of `bytes`.
+fn sha256_hex(bytes: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(bytes);
+    format!("{:x}", hasher.finalize())
+}
+
+/// Shared state for a single `get_subdir` run: the HTTP client, the semaphore
+/// bounding concurrent requests, the errors seen so far, and the lockfile
+/// state used for incremental syncing.
+struct DownloadCtx {
+    client: reqwest::Client,
+    semaphore: Semaphore,
+    errors: Mutex<Vec<String>>,
+    verify: bool,
+    old_lock: Option<Lockfile>,
+    lock_entries: Mutex<BTreeMap<PathBuf, String>>,
+}
+
+impl DownloadCtx {
+    fn new(client: reqwest::Client, jobs: usize, verify: bool, old_lock: Option<Lockfile>) -> DownloadCtx {
+        DownloadCtx {
+            client,
+            semaphore: Semaphore::new(jobs),
+            errors: Mutex::new(Vec::new()),
+            verify,
+            old_lock,
+            lock_entries: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn push_error(&self, msg: String) {
+        self.errors.lock().unwrap().push(msg);
+    }
+
+    fn push_lock_entry(&self, path: PathBuf, hash: String) {
+        self.lock_entries.lock().unwrap().insert(path, hash);
+    }
+}
+
+/// Build the shared `reqwest::Client` used for listing, resolving refs and
+/// downloading files.
+fn build_http_client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::limited(10))
+        .user_agent(concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")))
+        .build()
+        .expect("failed to build http client")
+}
+
+/// Download all items in a repo directory.
+///
+/// Note this function is called recursively (boxed, since it recurses through
+/// [`download`]). Each discovered item is spawned as its own task; the directory
+/// listing request and each file download (see [`download_file`]) are bounded
+/// by `ctx`'s semaphore, so a
+/// whole tree downloads concurrently rather than one file at a time, without
+/// exceeding `--jobs` simultaneous requests.
 ///
 /// # Arguments
 ///
-/// * `url` - [`GitHubUrl`] struct pointing to directory.
+/// * `ctx` - Shared client, concurrency limit and error list.
+/// * `url` - [`Repository`] pointing to directory.
 /// * `output` - Directory to write to.
 /// * `ignore_subdirs` - If `true`, don't download sub directories.
-/// * `relative_to` - If `Some(GithubUrl)`, write relative to url. Otherwise, write files relative to repo root.
+/// * `relative_to` - If `Some(Repository)`, write relative to url. Otherwise, write files relative to repo root.
 fn get_subdir(
-    url: &GitHubUrl,
-    output_path: &PathBuf,
+    ctx: Arc<DownloadCtx>,
+    url: Arc<dyn Repository>,
+    output_path: PathBuf,
     ignore_subdirs: bool,
-    relative_to: Option<&GitHubUrl>,
-) {
-    // note: using blocking instead of async because this function is called recursively
-    let text = reqwest::blocking::get(url.url()).unwrap().text().unwrap();
-
-    // find table of items in html
-    let document = Html::parse_document(&text);
-    let selector =
-        Selector::parse(r#"script[type="application/json"][data-target="react-app.embeddedData"]"#)
-            .unwrap();
-    for title in document.select(&selector) {
-        let v: Value = serde_json::from_str(&title.inner_html()).unwrap();
-        // get vector of items
-        let items = v["payload"]["tree"]["items"]
-            .as_array()
-            .unwrap()
-            .iter()
-            .map(|item| item.as_object().unwrap());
+    relative_to: Option<Arc<dyn Repository>>,
+) -> BoxFuture<'static, ()> {
+    Box::pin(async move {
+        let permit = ctx.semaphore.acquire().await.unwrap();
+        let listing = url.list_dir(&ctx.client).await;
+        drop(permit);
+
+        let items = match listing {
+            Ok(items) => items,
+            Err(e) => {
+                ctx.push_error(e);
+                return;
+            }
+        };
 
+        let mut handles = Vec::new();
         for item in items {
-            download(url, item, output_path, ignore_subdirs, relative_to);
+            let ctx = Arc::clone(&ctx);
+            let url = Arc::clone(&url);
+            let output_path = output_path.clone();
+            let relative_to = relative_to.clone();
+            handles.push(tokio::spawn(async move {
+                download(ctx, url, item, output_path, ignore_subdirs, relative_to).await
+            }));
         }
-    }
+
+        for result in join_all(handles).await {
+            if let Err(e) = result {
+                ctx.push_error(make_error_message(format!("task panicked: {}", e)));
+            }
+        }
+    })
 }
 
 /// Download item.
@@ -233,37 +1411,36 @@ fn get_subdir(
 ///
 /// # Arguments
 ///
-/// * `base_url` - [`GitHubUrl`] to download.
-/// * `item_info` - Map of info about the item to be downloaded.
+/// * `ctx` - Shared client, concurrency limit and error list.
+/// * `base_url` - [`Repository`] to download.
+/// * `item` - Listing entry to be downloaded.
 /// * `output_path` - Path to write to.
 /// * `ignore_subdirs` - If `true`, don't download anything if item is a directory.
 /// * `relative_to` - Optional url to write relative path to.
-fn download(
-    base_url: &GitHubUrl,
-    item_info: &serde_json::Map<String, serde_json::Value>,
-    output_path: &PathBuf,
+async fn download(
+    ctx: Arc<DownloadCtx>,
+    base_url: Arc<dyn Repository>,
+    item: RepoItem,
+    output_path: PathBuf,
     ignore_subdirs: bool,
-    relative_to: Option<&GitHubUrl>,
+    relative_to: Option<Arc<dyn Repository>>,
 ) {
-    let item_type = item_info["contentType"].as_str().unwrap();
-    let item_name = item_info["name"].as_str().unwrap();
-    let item_path = PathBuf::from(item_info["path"].as_str().unwrap());
-
     // url to item
-    let url = base_url.join(item_name);
+    let url = base_url.join(&item.name);
 
     // filename to write to
     // create from `output_path` with either abridged or full path
-    let mut filename = PathBuf::from(output_path);
-    let rel_path = match relative_to {
+    let mut filename = PathBuf::from(&output_path);
+    let rel_path = match &relative_to {
         Some(rel_url) => String::from(
-            item_path
-                .strip_prefix(rel_url.path.clone().to_str().unwrap())
+            item.path
+                .strip_prefix(rel_url.path())
                 .unwrap()
                 .to_str()
                 .unwrap(),
         ),
-        None => item_path
+        None => item
+            .path
             .components()
             .skip(1)
             .map(|p| p.as_os_str().to_str().unwrap())
@@ -272,62 +1449,151 @@ fn download(
     };
     filename.push(rel_path);
 
-    match item_type {
-        "file" => download_file(&url, &filename),
-        "directory" => {
+    match item.item_type {
+        RepoItemType::File => download_file(ctx, url, filename, item.path).await,
+        RepoItemType::Directory => {
             if !ignore_subdirs {
-                get_subdir(&url, output_path, ignore_subdirs, relative_to)
+                get_subdir(ctx, url, output_path, ignore_subdirs, relative_to).await
             }
         }
-        "symlink_file" | "symlink_directory" => {
+        RepoItemType::Symlink => {
             let msg =
-                make_warning_message(format!("Skipping symlink '{}'", url.path.to_str().unwrap()));
+                make_warning_message(format!("Skipping symlink '{}'", url.path().to_str().unwrap()));
             println!("{}", msg);
         }
-        _ => panic!("Cannot handle item type '{}'", item_type),
     }
 }
 
-/// Download given file
+/// Download given file.
+///
+/// If the lockfile from a previous run already has a hash for `repo_path` (i.e.
+/// the branch resolved to the same commit as last time) and the file still
+/// exists locally, the download is skipped. With `--verify`, the local file's
+/// hash is still recomputed and compared, to catch tampering or corruption.
+///
+/// The actual network request is bounded by `ctx`'s semaphore, so at most
+/// `--jobs` downloads are in flight at once, regardless of how many files a
+/// directory contains.
 ///
 /// # Arguments
 ///
-/// * `url` - [`GitHubUrl`] struct. This function gets the raw version of the url.
+/// * `ctx` - Shared client, error list and lockfile state.
+/// * `url` - [`Repository`]. This function gets the raw version of the url.
 /// * `filename` - Path to write to.
-fn download_file(url: &GitHubUrl, filename: &PathBuf) {
+/// * `repo_path` - Repo-relative path of this file, as recorded in the lockfile.
+async fn download_file(
+    ctx: Arc<DownloadCtx>,
+    url: Arc<dyn Repository>,
+    filename: PathBuf,
+    repo_path: PathBuf,
+) {
+    if let Some(expected_hash) = ctx
+        .old_lock
+        .as_ref()
+        .and_then(|lock| lock.files.get(&repo_path))
+    {
+        if filename.exists() {
+            if ctx.verify {
+                match fs::read(&filename) {
+                    Ok(bytes) if &sha256_hex(&bytes) != expected_hash => {
+                        println!(
+                            "{}",
+                            make_warning_message(format!(
+                                "hash mismatch for '{}': local copy may be corrupt or tampered with",
+                                filename.to_str().unwrap()
+                            ))
+                        );
+                    }
+                    Ok(_) => (),
+                    Err(e) => ctx.push_error(make_error_message(format!(
+                        "could not verify '{}': {}",
+                        filename.to_str().unwrap(),
+                        e
+                    ))),
+                }
+            }
+            println!("Skipping '{}' (unchanged)", filename.to_str().unwrap());
+            ctx.push_lock_entry(repo_path, expected_hash.clone());
+            return;
+        }
+    }
+
     let raw_url = url.raw_url();
 
-    let response = reqwest::blocking::get(raw_url).unwrap();
+    let permit = ctx.semaphore.acquire().await.unwrap();
+    let response = match ctx.client.get(&raw_url).send().await {
+        Ok(response) => response,
+        Err(e) => {
+            ctx.push_error(make_error_message(format!(
+                "could not fetch '{}': {}",
+                raw_url, e
+            )));
+            return;
+        }
+    };
 
-    let text = response.text().unwrap();
+    let bytes = match response.bytes().await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            ctx.push_error(make_error_message(format!(
+                "could not read '{}': {}",
+                raw_url, e
+            )));
+            return;
+        }
+    };
+    drop(permit);
 
     if !filename.parent().unwrap().exists() {
-        make_dir(filename.clone().parent().unwrap());
+        make_dir(filename.parent().unwrap());
     }
 
-    fs::write(filename, text).unwrap();
+    if let Err(e) = fs::write(&filename, &bytes) {
+        ctx.push_error(make_error_message(format!(
+            "could not write '{}': {}",
+            filename.to_str().unwrap(),
+            e
+        )));
+        return;
+    }
 
+    ctx.push_lock_entry(repo_path, sha256_hex(&bytes));
     println!("Downloaded '{}'", filename.to_str().unwrap());
 }
 
-/// Download directory from github
+/// Download directory from a git repo host
 ///
 /// # Arguments
 ///
-/// * `url` - String pointing directory in github repo.
+/// * `url` - String pointing to a directory in a GitHub/GitLab/Bitbucket repo.
 /// * `output` - Optional directory to write to. If `None`, inferred from url.
 /// * `ignore_subdirs` - If `true`, don't download sub directories.
 /// * `preserve_path_structure` - If `true`, write files relative to repo root. Otherwise, write relative to url.
-pub fn get_git_subdir(
+/// * `backend` - Which mechanism to fetch the directory with.
+/// * `jobs` - Maximum number of concurrent downloads (scrape backend only).
+/// * `verify` - Recompute local file hashes against the lockfile (scrape backend only).
+/// * `git_ref` - If `Some`, pin to this tag/commit/branch instead of whatever `url` implies.
+#[allow(clippy::too_many_arguments)]
+async fn get_git_subdir(
     url: &String,
     output: Option<String>,
     ignore_subdirs: bool,
     preserve_path_structure: bool,
+    backend: Backend,
+    jobs: usize,
+    verify: bool,
+    git_ref: Option<String>,
 ) {
-    let url = GitHubUrl::new(url);
+    let client = build_http_client();
+    let url = try_new(url, &client).await;
 
     match url {
         Ok(url) => {
+            let url = match git_ref {
+                Some(new_ref) => url.with_ref(&new_ref),
+                None => url,
+            };
+
             // if not given output path, use basename from url
             let output_path = match output {
                 Some(path) => PathBuf::from(path),
@@ -341,18 +1607,99 @@ pub fn get_git_subdir(
             let relative_to = if preserve_path_structure {
                 None
             } else {
-                Some(&url)
+                Some(Arc::clone(&url))
             };
 
-            get_subdir(&url, &output_path, ignore_subdirs, relative_to);
+            let use_git = match backend {
+                Backend::Git if git_available() => true,
+                Backend::Git => {
+                    let msg = make_warning_message(String::from(
+                        "git backend requested but 'git' was not found on PATH; falling back to scrape",
+                    ));
+                    println!("{}", msg);
+                    false
+                }
+                Backend::Scrape => false,
+            };
+
+            if use_git {
+                if let Err(s) = get_subdir_git(
+                    url.as_ref(),
+                    &output_path,
+                    ignore_subdirs,
+                    relative_to.as_deref(),
+                ) {
+                    println!("{s}");
+                }
+            } else {
+                let resolved_commit = match url.resolve_ref(&client).await {
+                    Ok(commit) => Some(commit),
+                    Err(e) => {
+                        println!(
+                            "{}",
+                            make_warning_message(format!(
+                                "could not resolve ref, lockfile will not be written: {}",
+                                e
+                            ))
+                        );
+                        None
+                    }
+                };
+
+                let lock_path = lockfile_path(&output_path);
+                let old_lock = read_lockfile(&lock_path).filter(|lock| {
+                    resolved_commit
+                        .as_ref()
+                        .map(|commit| &lock.commit == commit)
+                        .unwrap_or(false)
+                });
+
+                let ctx = Arc::new(DownloadCtx::new(client, jobs, verify, old_lock));
+                get_subdir(Arc::clone(&ctx), url, output_path, ignore_subdirs, relative_to).await;
+
+                let errors = ctx.errors.lock().unwrap();
+                if !errors.is_empty() {
+                    println!(
+                        "{}",
+                        make_warning_message(format!(
+                            "{} item(s) failed to download:",
+                            errors.len()
+                        ))
+                    );
+                    for e in errors.iter() {
+                        println!("{e}");
+                    }
+                }
+
+                if let Some(commit) = resolved_commit {
+                    let lock = Lockfile {
+                        commit,
+                        files: ctx.lock_entries.lock().unwrap().clone(),
+                    };
+                    if let Err(e) = write_lockfile(&lock_path, &lock) {
+                        println!("{e}");
+                    }
+                }
+            }
         }
         Err(s) => println!("{s}"),
     }
 }
 
-fn main() {
+#[tokio::main]
+async fn main() {
     let cli = Cli::parse();
-    get_git_subdir(&cli.url, cli.output, cli.ignore_subdirs, cli.relative);
+    get_git_subdir(
+        &cli.url,
+        cli.output,
+        cli.ignore_subdirs,
+        cli.relative,
+        cli.backend,
+        cli.jobs,
+        cli.verify,
+        cli.git_ref,
+    )
+    .await;
 }
 
 // TESTS
@@ -360,13 +1707,13 @@ fn main() {
 mod tests {
     use super::*;
     use rstest::rstest;
-    #[test]
-    fn test_default_args() {
+    #[tokio::test]
+    async fn test_default_args() {
         // test default behaviour
 
         let url = String::from("https://github.com/keziah55/ABBAd_day/tree/master/ABBAd_day");
 
-        get_git_subdir(&url, None, false, false);
+        get_git_subdir(&url, None, false, false, Backend::Scrape, 8, false, None).await;
 
         let expected_path = PathBuf::from("ABBAd_day");
         assert!(expected_path.exists());
@@ -378,8 +1725,8 @@ mod tests {
         fs::remove_dir_all(expected_path).unwrap();
     }
 
-    #[test]
-    fn test_custom_output_path() {
+    #[tokio::test]
+    async fn test_custom_output_path() {
         // test set custom outdir for contents
 
         let url = String::from("https://github.com/keziah55/git-subdir/tree/main/src");
@@ -388,7 +1735,7 @@ mod tests {
         let expected_path = output_path.clone();
         let output_path_arg = Some(output_path.into_os_string().into_string().unwrap());
 
-        get_git_subdir(&url, output_path_arg, false, false);
+        get_git_subdir(&url, output_path_arg, false, false, Backend::Scrape, 8, false, None).await;
 
         assert!(expected_path.exists());
         let filepath = expected_path.join("main.rs");
@@ -403,13 +1750,39 @@ mod tests {
         fs::remove_dir_all(expected_path).unwrap();
     }
 
-    #[test]
-    fn test_ignore_subdirs() {
+    #[tokio::test]
+    async fn test_lockfile_written() {
+        // test that a lockfile is written alongside the downloaded files
+
+        let url = String::from("https://github.com/keziah55/git-subdir/tree/main/src");
+
+        let output_path = PathBuf::from("tmp_test_lock");
+        let expected_path = output_path.clone();
+        let output_path_arg = Some(output_path.into_os_string().into_string().unwrap());
+
+        get_git_subdir(&url, output_path_arg, false, false, Backend::Scrape, 8, false, None).await;
+
+        let lock_path = expected_path.join("git-subdir.lock");
+        assert!(lock_path.exists());
+
+        let lock: Lockfile = serde_json::from_str(&fs::read_to_string(&lock_path).unwrap()).unwrap();
+        assert!(!lock.commit.is_empty());
+        let hash = lock
+            .files
+            .get(&PathBuf::from("src/main.rs"))
+            .expect("expected lockfile entry for src/main.rs");
+        assert_eq!(hash.len(), 64, "expected a hex-encoded sha256 digest");
+
+        fs::remove_dir_all(expected_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_ignore_subdirs() {
         // test don't get subdirs
 
         let url = String::from("https://github.com/keziah55/pick/tree/main/mediabrowser");
 
-        get_git_subdir(&url, None, true, false);
+        get_git_subdir(&url, None, true, false, Backend::Scrape, 8, false, None).await;
 
         let output_path = PathBuf::from("mediabrowser");
         let expected_path = output_path.clone();
@@ -423,8 +1796,8 @@ mod tests {
         fs::remove_dir_all(output_path).unwrap();
     }
 
-    #[test]
-    fn test_relative_path() {
+    #[tokio::test]
+    async fn test_relative_path() {
         // test preserving dir structure, relative to git repo root
 
         let url = String::from(
@@ -435,7 +1808,7 @@ mod tests {
         let mut expected_path = output_path.clone();
         let output_path_arg = Some(output_path.clone().into_os_string().into_string().unwrap());
 
-        get_git_subdir(&url, output_path_arg, false, true);
+        get_git_subdir(&url, output_path_arg, false, true, Backend::Scrape, 8, false, None).await;
 
         expected_path.push("templates/mediabrowser");
 
@@ -451,7 +1824,10 @@ mod tests {
     }
 
     #[rstest]
-    #[case(String::from("https://some-other.url"), "is not a github url")]
+    #[case(
+        String::from("https://some-other.url"),
+        "is not a recognised GitHub, GitLab or Bitbucket url"
+    )]
     #[case(
         String::from("https://github.com/username/repo"),
         "is a top-level git repo"
@@ -464,9 +1840,22 @@ mod tests {
         String::from("https://github.com/username/repo/not_tree/branch.dir"),
         "cannot parse url"
     )]
-    fn test_invalid_url(#[case] url: String, #[case] expected_msg: &str) {
-        // let url = String::from("https://some-other.url");
-        let result = GitHubUrl::new(&url);
+    #[case(
+        String::from("https://gitlab.com/username/repo/not_tree/branch/dir"),
+        "cannot parse url"
+    )]
+    #[case(
+        String::from("https://bitbucket.org/username/repo/not_src/branch/dir"),
+        "cannot parse url"
+    )]
+    #[case(
+        String::from("https://github.com/username/repo.git#main"),
+        "missing a ':<path>' after the ref"
+    )]
+    #[tokio::test]
+    async fn test_invalid_url(#[case] url: String, #[case] expected_msg: &str) {
+        let client = build_http_client();
+        let result = try_new(&url, &client).await;
         assert!(result.is_err());
 
         match result {
@@ -479,4 +1868,102 @@ mod tests {
             Ok(_) => (),
         }
     }
+
+    #[rstest]
+    #[case(
+        "gh:keziah55/ABBAd_day/tree/master/ABBAd_day",
+        "https://github.com/keziah55/ABBAd_day/tree/master/ABBAd_day"
+    )]
+    #[case(
+        "gl:username/repo/-/tree/branch/dir",
+        "https://gitlab.com/username/repo/-/tree/branch/dir"
+    )]
+    #[case(
+        "bb:username/repo/src/branch/dir",
+        "https://bitbucket.org/username/repo/src/branch/dir"
+    )]
+    #[tokio::test]
+    async fn test_alias_urls(#[case] alias: &str, #[case] expected_url: &str) {
+        let client = build_http_client();
+        let repo = try_new(alias, &client).await.unwrap();
+        assert_eq!(repo.url(), expected_url);
+    }
+
+    #[test]
+    fn test_parse_explicit_ref() {
+        let prefix = "https://github.com";
+        let url = format!("{}/keziah55/git-subdir.git#main:src", prefix);
+        let (username, repo_name, branch, path) = parse_explicit_ref(&url, prefix).unwrap().unwrap();
+        assert_eq!(username, "keziah55");
+        assert_eq!(repo_name, "git-subdir");
+        assert_eq!(branch, "main");
+        assert_eq!(path, PathBuf::from("src"));
+    }
+
+    #[test]
+    fn test_parse_explicit_ref_no_hash_returns_none() {
+        let prefix = "https://github.com";
+        let url = format!("{}/keziah55/git-subdir/tree/main/src", prefix);
+        assert!(parse_explicit_ref(&url, prefix).is_none());
+    }
+
+    #[rstest]
+    #[case(vec![String::from("feature/foo"), String::from("main")], vec!["feature", "foo", "src"], "feature/foo", "src")]
+    #[case(Vec::<String>::new(), vec!["main", "src"], "main", "src")]
+    #[case(vec![String::from("main")], vec!["main"], "main", "")]
+    fn test_resolve_ref_boundary(
+        #[case] refs: Vec<String>,
+        #[case] ambiguous: Vec<&str>,
+        #[case] expected_branch: &str,
+        #[case] expected_path: &str,
+    ) {
+        let (branch, path) = resolve_ref_boundary(&refs, &ambiguous);
+        assert_eq!(branch, expected_branch);
+        assert_eq!(path, PathBuf::from(expected_path));
+    }
+
+    #[tokio::test]
+    async fn test_explicit_ref_syntax() {
+        // test downloading via the `repo.git#ref:path` syntax
+
+        let url = String::from("https://github.com/keziah55/git-subdir.git#main:src");
+
+        let output_path = PathBuf::from("tmp_test_explicit_ref");
+        let expected_path = output_path.clone();
+        let output_path_arg = Some(output_path.into_os_string().into_string().unwrap());
+
+        get_git_subdir(&url, output_path_arg, false, false, Backend::Scrape, 8, false, None).await;
+
+        assert!(expected_path.join("main.rs").exists());
+
+        fs::remove_dir_all(expected_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_ref_override() {
+        // test that `--ref` overrides the branch implied by the url, even
+        // when that branch doesn't exist
+
+        let url = String::from("https://github.com/keziah55/git-subdir/tree/nonexistent-branch/src");
+
+        let output_path = PathBuf::from("tmp_test_ref_override");
+        let expected_path = output_path.clone();
+        let output_path_arg = Some(output_path.into_os_string().into_string().unwrap());
+
+        get_git_subdir(
+            &url,
+            output_path_arg,
+            false,
+            false,
+            Backend::Scrape,
+            8,
+            false,
+            Some(String::from("main")),
+        )
+        .await;
+
+        assert!(expected_path.join("main.rs").exists());
+
+        fs::remove_dir_all(expected_path).unwrap();
+    }
 }